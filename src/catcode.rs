@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+/// One of TeX's sixteen category codes, assigning a character its lexical role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CatCode {
+    /// Starts a control sequence, e.g. `\`.
+    Escape,
+    /// Opens a group, e.g. `{`.
+    BeginGroup,
+    /// Closes a group, e.g. `}`.
+    EndGroup,
+    /// Enters/leaves math mode, e.g. `$`.
+    MathShift,
+    /// Table column separator, e.g. `&`.
+    AlignTab,
+    /// Treated as a space, but ends a paragraph if doubled.
+    EndOfLine,
+    /// Marks a macro parameter, e.g. `#`.
+    Parameter,
+    /// Marks a superscript in math mode, e.g. `^`.
+    Superscript,
+    /// Marks a subscript in math mode, e.g. `_`.
+    Subscript,
+    /// Dropped from the input entirely.
+    Ignored,
+    /// Ordinary whitespace.
+    Space,
+    /// A letter, usable in control sequence names.
+    Letter,
+    /// Anything with no special meaning.
+    Other,
+    /// Behaves like a control sequence of a single character, e.g. `~`.
+    Active,
+    /// Starts a comment, running to the end of the line, e.g. `%`.
+    Comment,
+    /// Not allowed in input at all.
+    Invalid,
+}
+
+/// Maps characters to their TeX category code. Characters not explicitly assigned a code fall
+/// back to [`CatCode::Other`], mirroring TeX's own default table.
+#[derive(Debug, Clone)]
+pub struct CatCodeTable {
+    codes: HashMap<char, CatCode>,
+}
+
+impl CatCodeTable {
+    /// Builds TeX's default category-code table.
+    pub fn tex_defaults() -> CatCodeTable {
+        let mut codes = HashMap::new();
+        codes.insert('\\', CatCode::Escape);
+        codes.insert('{', CatCode::BeginGroup);
+        codes.insert('}', CatCode::EndGroup);
+        codes.insert('$', CatCode::MathShift);
+        codes.insert('&', CatCode::AlignTab);
+        codes.insert('\n', CatCode::EndOfLine);
+        codes.insert('#', CatCode::Parameter);
+        codes.insert('^', CatCode::Superscript);
+        codes.insert('_', CatCode::Subscript);
+        codes.insert(' ', CatCode::Space);
+        codes.insert('~', CatCode::Active);
+        codes.insert('%', CatCode::Comment);
+        codes.insert('\x7f', CatCode::Invalid);
+        for letter in ('a'..='z').chain('A'..='Z') {
+            codes.insert(letter, CatCode::Letter);
+        }
+        CatCodeTable { codes }
+    }
+
+    /// Looks up a character's category code, defaulting to [`CatCode::Other`] if it hasn't been
+    /// assigned one.
+    pub fn get(&self, ch: char) -> CatCode {
+        self.codes.get(&ch).copied().unwrap_or(CatCode::Other)
+    }
+
+    /// Assigns a character a category code, e.g. to emulate TeX's `\catcode` assignment.
+    pub fn set(&mut self, ch: char, code: CatCode) {
+        self.codes.insert(ch, code);
+    }
+}
+
+impl Default for CatCodeTable {
+    fn default() -> CatCodeTable {
+        CatCodeTable::tex_defaults()
+    }
+}
@@ -4,24 +4,36 @@ use std::{
 };
 
 use crate::{
+    catcode::{CatCode, CatCodeTable},
     util::{PosChars, WithPosChars},
-    Error, Pos, Side, Token,
+    Diagnostic, Error, Pos, Side, Span, Token,
 };
 
 /// Lexer (tokeniser) for latex maths mode code. TeX calls this the mouth. The public interface is
-/// an iterator over the lexed tokens.
+/// an iterator over the lexed tokens. Illegal characters are recoverable: rather than being
+/// surfaced inline as a `Token::Err`, they are recorded as a [`Diagnostic`] and lexing continues
+/// past them, so a single pass can report every problem in the source at once.
 pub struct Lexer<I>
 where
     I: Iterator<Item = char>,
 {
     chars: Peekable<PosChars<I>>,
+    catcodes: CatCodeTable,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl<'a> Lexer<Chars<'a>> {
-    /// Create a lexer for a given `&str`.
+    /// Create a lexer for a given `&str`, using TeX's default category codes.
     pub fn new(code: &'a str) -> Lexer<Chars<'a>> {
+        Lexer::with_catcodes(code, CatCodeTable::default())
+    }
+
+    /// Create a lexer for a given `&str`, using a custom category-code table.
+    pub fn with_catcodes(code: &'a str, catcodes: CatCodeTable) -> Lexer<Chars<'a>> {
         Lexer {
             chars: code.chars().with_pos().peekable(),
+            catcodes,
+            diagnostics: Vec::new(),
         }
     }
 }
@@ -30,57 +42,119 @@ impl<I> Lexer<I>
 where
     I: Iterator<Item = char>,
 {
+    /// Assigns a character a category code, e.g. to honour a `\catcode` assignment encountered
+    /// mid-stream.
+    pub fn set_catcode(&mut self, ch: char, code: CatCode) {
+        self.catcodes.set(ch, code);
+    }
+
+    /// Diagnostics accumulated so far.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Consumes the lexer, returning every diagnostic accumulated over its lifetime. Typically
+    /// called once the token stream has been fully drained.
+    pub fn finish(self) -> Vec<Diagnostic> {
+        self.diagnostics
+    }
+
     /// Get the next token
     fn next_token(&mut self) -> Option<Pos<Token>> {
         use Side::*;
         use Token::*;
-        self.chars.next().map(|pch| {
-            pch.map(|ch| match ch {
-                '{' => Brace(Left),
-                '}' => Brace(Right),
-                '&' => Ampersand,
-                '\0' => Eof,
-                '\\' => Control(self.chars.next().map_or("".into(), |pch| {
-                    self.collect_command(pch.val, String::new())
-                })),
-                '%' => Comment(self.build_comment(String::new())),
-                '#' => self
-                    .chars
-                    .next()
-                    .map_or(Err(Error::IllegalChar('#')), |pch| {
-                        if pch.val.is_ascii_digit() {
-                            Arg(self.collect_arg(pch.val, String::new()))
-                        } else {
-                            Err(Error::IllegalChar('#'))
-                        }
-                    }),
-                _ if ch.is_whitespace() => Whitespace(self.collect_whitespace(ch, String::new())),
-                _ => Char(ch),
-            })
-        })
+
+        loop {
+            let pch = self.chars.next()?;
+            let start = pch.span;
+            let ch = pch.val;
+
+            // The EOF sentinel is recognised independently of its category code.
+            if ch == '\0' {
+                return Some(Pos::new(Eof, start));
+            }
+
+            let (val, end) = match self.catcodes.get(ch) {
+                CatCode::Escape => match self.chars.next() {
+                    Some(pch) => {
+                        let (name, end) = self.collect_command(pch.val, pch.span, String::new());
+                        (Control(name), end)
+                    }
+                    None => (Control(String::new()), start),
+                },
+                CatCode::BeginGroup => (Brace(Left), start),
+                CatCode::EndGroup => (Brace(Right), start),
+                CatCode::MathShift => (MathShift, start),
+                CatCode::AlignTab => (Ampersand, start),
+                CatCode::EndOfLine | CatCode::Space => {
+                    let (ws, end) = self.collect_whitespace(ch, start, String::new());
+                    if ws.chars().filter(|&ch| ch == '\n').count() >= 2 {
+                        (Par, end)
+                    } else {
+                        (Whitespace(ws), end)
+                    }
+                }
+                CatCode::Parameter => match self.chars.next_if(|pch| pch.val.is_ascii_digit()) {
+                    Some(pch) => {
+                        let (arg, end) = self.collect_arg(pch.val, pch.span, String::new());
+                        (Arg(arg), end)
+                    }
+                    None => {
+                        // Leave whatever follows `#` alone, recovering at the very next
+                        // character rather than swallowing it.
+                        self.diagnostics
+                            .push(Diagnostic::new(Error::IllegalChar(ch), start));
+                        continue;
+                    }
+                },
+                CatCode::Superscript => (Superscript, start),
+                CatCode::Subscript => (Subscript, start),
+                CatCode::Ignored => continue,
+                CatCode::Letter | CatCode::Other => (Char(ch), start),
+                CatCode::Active => (Active(ch), start),
+                CatCode::Comment => {
+                    let (comment, end) = self.build_comment(start, String::new());
+                    (Comment(comment), end)
+                }
+                CatCode::Invalid => {
+                    self.diagnostics
+                        .push(Diagnostic::new(Error::IllegalChar(ch), start));
+                    continue;
+                }
+            };
+
+            return Some(Pos::new(val, start.join(&end)));
+        }
     }
 
     // Build states start from the first character
 
-    /// Builds a comment string, starting from the current char.
-    fn build_comment(&mut self, mut buffer: String) -> String {
+    /// Builds a comment string, starting from the current char. `current_span` is the span of
+    /// the last character consumed so far, used as the result's end if no further characters
+    /// belong to the comment.
+    fn build_comment(&mut self, current_span: Span, mut buffer: String) -> (String, Span) {
         match self.chars.next_if(|ch| ch.val != '\n' && ch.val != '\0') {
             Some(ch) => {
                 buffer.push(ch.val);
-                self.build_comment(buffer)
+                self.build_comment(ch.span, buffer)
             }
-            None => buffer,
+            None => (buffer, current_span),
         }
     }
 
     // Collect states must be provided with the first character
 
     /// Collects a command string, starting from the provided char and continuing by iterating over `self.chars`.
-    fn collect_command(&mut self, current: char, mut buffer: String) -> String {
+    fn collect_command(
+        &mut self,
+        current: char,
+        current_span: Span,
+        mut buffer: String,
+    ) -> (String, Span) {
         buffer.push(current);
         match self.chars.next_if(|ch| ch.val.is_ascii_alphabetic()) {
-            Some(ch) => self.collect_command(ch.val, buffer),
-            None => buffer,
+            Some(ch) => self.collect_command(ch.val, ch.span, buffer),
+            None => (buffer, current_span),
         }
     }
 
@@ -89,22 +163,39 @@ where
     /// # Panics
     ///
     /// Panics if provided `current` is not a valid ascii digit.
-    fn collect_arg(&mut self, current: char, mut buffer: String) -> usize {
+    fn collect_arg(
+        &mut self,
+        current: char,
+        current_span: Span,
+        mut buffer: String,
+    ) -> (usize, Span) {
         buffer.push(current);
         match self.chars.next_if(|ch| ch.val.is_ascii_digit()) {
-            Some(ch) => self.collect_arg(ch.val, buffer),
-            None => buffer
-                .parse()
-                .expect("`buffer` should only contain ASCII digits."),
+            Some(ch) => self.collect_arg(ch.val, ch.span, buffer),
+            None => (
+                buffer
+                    .parse()
+                    .expect("`buffer` should only contain ASCII digits."),
+                current_span,
+            ),
         }
     }
 
-    /// Collects whitespace string, starting from the provided char (assumed to be whitespace) and continuing by iterating over `self.chars`
-    fn collect_whitespace(&mut self, current: char, mut buffer: String) -> String {
+    /// Collects whitespace string, starting from the provided char (assumed to have catcode
+    /// `Space` or `EndOfLine`) and continuing by iterating over `self.chars`.
+    fn collect_whitespace(
+        &mut self,
+        current: char,
+        current_span: Span,
+        mut buffer: String,
+    ) -> (String, Span) {
         buffer.push(current);
-        match self.chars.next_if(|ch| ch.val.is_whitespace()) {
-            Some(ch) => self.collect_whitespace(ch.val, buffer),
-            None => buffer,
+        let catcodes = &self.catcodes;
+        match self.chars.next_if(|ch| {
+            matches!(catcodes.get(ch.val), CatCode::Space | CatCode::EndOfLine)
+        }) {
+            Some(ch) => self.collect_whitespace(ch.val, ch.span, buffer),
+            None => (buffer, current_span),
         }
     }
 }
@@ -133,7 +224,7 @@ impl<I> FusedIterator for Lexer<I> where I: Iterator<Item = char> + FusedIterato
 
 #[cfg(test)]
 mod tests {
-    use crate::{Error, Lexer, Side::*, Token::*};
+    use crate::{lexer::Lexer, Error, Side::*, Token::*};
 
     const EXAMPLE_LATEX: &str = r#"
 \newcommand{\u}[1]{2^#1}
@@ -153,12 +244,12 @@ mod tests {
             Char(']'),
             Brace(Left),
             Char('2'),
-            Char('^'),
+            Superscript,
             Arg(1),
             Brace(Right),
             Whitespace("\n".into()),
             Char('3'),
-            Char('^'),
+            Superscript,
             Char('x'),
             Whitespace(" ".into()),
             Ampersand,
@@ -172,13 +263,94 @@ mod tests {
             Whitespace(" ".into()),
             Comment(" I'm a comment.".into()),
             Whitespace("\n".into()),
-            Err(Error::IllegalChar('#')),
+            // The final `#` is illegal and is now reported as a diagnostic instead of a token.
         ];
 
-        let lexer = Lexer::new(EXAMPLE_LATEX);
+        let mut lexer = Lexer::new(EXAMPLE_LATEX);
+        let tokens: Vec<_> = (&mut lexer).map(|ch| ch.val).collect();
 
-        for (a, b) in lexer.map(|ch| ch.val).zip(example_latex_tokenized) {
+        for (a, b) in tokens.into_iter().zip(example_latex_tokenized) {
             assert_eq!(a, b);
         }
+
+        assert_eq!(lexer.diagnostics().len(), 1);
+        assert_eq!(lexer.diagnostics()[0].error, Error::IllegalChar('#'));
+    }
+
+    #[test]
+    fn illegal_chars_are_recovered_and_lexing_continues() {
+        let mut lexer = Lexer::new("a#b#c");
+        let tokens: Vec<_> = (&mut lexer).map(|ch| ch.val).collect();
+
+        assert_eq!(tokens, vec![Char('a'), Char('b'), Char('c')]);
+        assert_eq!(
+            lexer
+                .finish()
+                .into_iter()
+                .map(|diagnostic| diagnostic.error)
+                .collect::<Vec<_>>(),
+            vec![Error::IllegalChar('#'), Error::IllegalChar('#')]
+        );
+    }
+
+    #[test]
+    fn default_catcodes_tokenize_math_shift_and_active() {
+        let lexer = Lexer::new("$x~y_z");
+        let tokens: Vec<_> = lexer.map(|ch| ch.val).collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                MathShift,
+                Char('x'),
+                Active('~'),
+                Char('y'),
+                Subscript,
+                Char('z'),
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_catcodes_override_defaults() {
+        use crate::catcode::{CatCode, CatCodeTable};
+
+        let mut catcodes = CatCodeTable::default();
+        catcodes.set('$', CatCode::Other);
+
+        let lexer = Lexer::with_catcodes("$x", catcodes);
+        let tokens: Vec<_> = lexer.map(|ch| ch.val).collect();
+
+        assert_eq!(tokens, vec![Char('$'), Char('x')]);
+    }
+
+    #[test]
+    fn blank_line_emits_par_but_single_newline_stays_whitespace() {
+        let lexer = Lexer::new("a\nb\n\nc\n\n\nd");
+        let tokens: Vec<_> = lexer.map(|ch| ch.val).collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                Char('a'),
+                Whitespace("\n".into()),
+                Char('b'),
+                Par,
+                Char('c'),
+                Par,
+                Char('d'),
+            ]
+        );
+    }
+
+    #[test]
+    fn set_catcode_mid_stream_affects_later_tokens() {
+        use crate::catcode::CatCode;
+
+        let mut lexer = Lexer::new("$$");
+        assert_eq!(lexer.next().map(|ch| ch.val), Some(MathShift));
+
+        lexer.set_catcode('$', CatCode::Other);
+        assert_eq!(lexer.next().map(|ch| ch.val), Some(Char('$')));
     }
 }
@@ -1,3 +1,4 @@
+pub mod catcode;
 pub mod lexer;
 pub mod macro_expander;
 pub mod macros;
@@ -1,39 +1,345 @@
-use std::iter::FusedIterator;
+use std::{
+    collections::{HashMap, VecDeque},
+    iter::FusedIterator,
+};
 
-use crate::{Pos, Token};
+use crate::{
+    macros::{TexMacro, TexMacroCtx},
+    Error, Pos, Side, Span, Token,
+};
 
-// TODO: Implement MacroExpander
+/// Default limit on how many macro expansions [`ExpandMacros::next`] will perform in a row
+/// before giving up. This catches macros that (directly or indirectly) expand to themselves
+/// before producing any other token, e.g. `\def\a{\a}`, rather than looping forever. The count
+/// restarts with each call to `next`, so a macro that emits a token before invoking itself again,
+/// e.g. `\def\a{x\a}`, is not caught.
+const DEFAULT_MAX_EXPANSION_DEPTH: usize = 256;
+
+/// A macro definition, as captured from `\newcommand`/`\def` or registered as a [`TexMacro`].
+#[derive(Clone)]
+enum MacroDef {
+    /// A user-defined macro: an arity plus a replacement token list containing `Token::Arg(n)`
+    /// placeholders for its parameters.
+    User {
+        arity: usize,
+        replacement: Vec<Token>,
+    },
+    /// A procedural macro registered ahead of time, e.g. via the `tex_macro` attribute.
+    Builtin(&'static TexMacro),
+}
 
 /// Expands macros resulting in TeX that is completely token-free. TeX calls this the gullet.
 pub struct ExpandMacros<I> {
     lexer: I,
+    macros: HashMap<String, MacroDef>,
+    /// Tokens waiting to be re-read, in order, before pulling more from `lexer`. Populated by
+    /// macro expansion and by the one-token lookahead `define_macro` needs.
+    pushback: VecDeque<Pos<Token>>,
+    max_depth: usize,
 }
 
 impl<I: Iterator<Item = Pos<Token>>> ExpandMacros<I> {
     pub fn new(lexer: I) -> ExpandMacros<I> {
-        ExpandMacros { lexer }
+        Self::with_max_depth(lexer, DEFAULT_MAX_EXPANSION_DEPTH)
+    }
+
+    /// Creates an expander with a custom limit on consecutive macro expansions (see
+    /// [`DEFAULT_MAX_EXPANSION_DEPTH`]).
+    pub fn with_max_depth(lexer: I, max_depth: usize) -> ExpandMacros<I> {
+        ExpandMacros {
+            lexer,
+            macros: HashMap::new(),
+            pushback: VecDeque::new(),
+            max_depth,
+        }
+    }
+
+    /// Registers a procedural macro under all of its names, making it available for expansion.
+    pub fn register(&mut self, texmacro: &'static TexMacro) {
+        for name in texmacro.names {
+            self.macros
+                .insert((*name).to_string(), MacroDef::Builtin(texmacro));
+        }
+    }
+
+    /// Pops the next token, preferring anything already in `pushback`.
+    fn next_raw(&mut self) -> Option<Pos<Token>> {
+        self.pushback.pop_front().or_else(|| self.lexer.next())
+    }
+
+    /// Peeks at the next token without consuming it.
+    fn peek_raw(&mut self) -> Option<&Pos<Token>> {
+        if self.pushback.front().is_none() {
+            let tok = self.lexer.next()?;
+            self.pushback.push_back(tok);
+        }
+        self.pushback.front()
+    }
+
+    /// Reads a single macro argument: the next non-space token, or, if that token is `{`, the
+    /// balanced brace-delimited group it opens (braces excluded).
+    fn read_argument(&mut self) -> Vec<Token> {
+        loop {
+            match self.next_raw() {
+                Some(Pos {
+                    val: Token::Whitespace(_) | Token::Par,
+                    ..
+                }) => continue,
+                Some(Pos {
+                    val: Token::Brace(Side::Left),
+                    ..
+                }) => {
+                    return self
+                        .read_balanced_group()
+                        .into_iter()
+                        .map(|pos| pos.val)
+                        .collect()
+                }
+                Some(pos) => return vec![pos.val],
+                None => return Vec::new(),
+            }
+        }
+    }
+
+    /// Reads tokens up to (and consuming) the matching `}`, assuming the opening `{` has already
+    /// been consumed. Nested braces are kept in the returned tokens; the outer pair is not.
+    fn read_balanced_group(&mut self) -> Vec<Pos<Token>> {
+        let mut depth = 1usize;
+        let mut tokens = Vec::new();
+        while depth > 0 {
+            match self.next_raw() {
+                Some(pos) => match &pos.val {
+                    Token::Brace(Side::Left) => {
+                        depth += 1;
+                        tokens.push(pos);
+                    }
+                    Token::Brace(Side::Right) => {
+                        depth -= 1;
+                        if depth > 0 {
+                            tokens.push(pos);
+                        }
+                    }
+                    _ => tokens.push(pos),
+                },
+                None => break,
+            }
+        }
+        tokens
+    }
+
+    /// Parses a `\newcommand{\name}[arity]{replacement}` or `\def\name#1...#n{replacement}`
+    /// invocation (the control sequence itself has already been consumed) and records the
+    /// resulting definition in the macro table.
+    fn define_macro(&mut self) {
+        let name = match self.next_raw() {
+            Some(Pos {
+                val: Token::Brace(Side::Left),
+                ..
+            }) => {
+                let name = match self.next_raw() {
+                    Some(Pos {
+                        val: Token::Control(name),
+                        ..
+                    }) => name,
+                    _ => return,
+                };
+                self.next_raw(); // closing `}`
+                name
+            }
+            Some(Pos {
+                val: Token::Control(name),
+                ..
+            }) => name,
+            _ => return,
+        };
+
+        let mut arity = 0;
+        loop {
+            match self.peek_raw().map(|pos| &pos.val) {
+                Some(Token::Char('[')) => {
+                    self.next_raw();
+                    if let Some(Pos {
+                        val: Token::Char(digit),
+                        ..
+                    }) = self.next_raw()
+                    {
+                        arity = digit.to_digit(10).unwrap_or(0) as usize;
+                    }
+                    if matches!(self.peek_raw().map(|pos| &pos.val), Some(Token::Char(']'))) {
+                        self.next_raw();
+                    }
+                    break;
+                }
+                Some(Token::Arg(n)) => {
+                    arity = arity.max(*n);
+                    self.next_raw();
+                }
+                _ => break,
+            }
+        }
+
+        let body = match self.next_raw() {
+            Some(Pos {
+                val: Token::Brace(Side::Left),
+                ..
+            }) => self.read_balanced_group(),
+            _ => return,
+        };
+        let replacement = body.into_iter().map(|pos| pos.val).collect();
+
+        self.macros
+            .insert(name, MacroDef::User { arity, replacement });
     }
 }
 
+/// Substitutes each `Token::Arg(n)` in `replacement` with the corresponding collected argument,
+/// wrapping every resulting token with `span` (the invoking control sequence's span; the spans of
+/// the invocation's arguments are not joined in).
+fn substitute(replacement: &[Token], args: &[Vec<Token>], span: Span) -> VecDeque<Pos<Token>> {
+    replacement
+        .iter()
+        .flat_map(|tok| match tok {
+            Token::Arg(n) if *n >= 1 && *n <= args.len() => args[*n - 1]
+                .iter()
+                .cloned()
+                .map(|tok| Pos::new(tok, span))
+                .collect(),
+            other => vec![Pos::new(other.clone(), span)],
+        })
+        .collect()
+}
+
 impl<I: Iterator<Item = Pos<Token>>> Iterator for ExpandMacros<I> {
     type Item = Pos<Token>;
 
     fn next(&mut self) -> Option<Pos<Token>> {
-        self.lexer.next()
+        let mut depth = 0;
+        loop {
+            let tok = self.next_raw()?;
+            let name = match &tok.val {
+                Token::Control(name) => name,
+                _ => return Some(tok),
+            };
+
+            if name == "newcommand" || name == "def" {
+                self.define_macro();
+                continue;
+            }
+
+            let def = match self.macros.get(name).cloned() {
+                Some(def) => def,
+                None => return Some(tok),
+            };
+
+            depth += 1;
+            if depth > self.max_depth {
+                return Some(Pos::new(Token::Err(Error::ExpansionDepthExceeded), tok.span));
+            }
+
+            let tok_span = tok.span;
+            let result_tokens = match def {
+                MacroDef::User { arity, replacement } => {
+                    let args: Vec<Vec<Token>> =
+                        (0..arity).map(|_| self.read_argument()).collect();
+                    substitute(&replacement, &args, tok_span)
+                }
+                MacroDef::Builtin(texmacro) => {
+                    let args: Vec<Vec<Token>> = (0..texmacro.arity)
+                        .map(|_| self.read_argument())
+                        .collect();
+                    let result = (texmacro.fun)(TexMacroCtx { args });
+                    result
+                        .tokens
+                        .into_iter()
+                        .map(|tok| Pos::new(tok, tok_span))
+                        .collect()
+                }
+            };
+
+            for pos_tok in result_tokens.into_iter().rev() {
+                self.pushback.push_front(pos_tok);
+            }
+        }
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        self.lexer.size_hint()
+        (0, None)
     }
 }
 
 impl<I> FusedIterator for ExpandMacros<I> where I: Iterator<Item = Pos<Token>> + FusedIterator {}
 
 trait WithExpandMacros: Iterator<Item = Pos<Token>> + Sized {
-    /// Expand each token in an iterator.
+    /// Expand each macro invocation in an iterator of tokens.
     fn expand_macros(self) -> ExpandMacros<Self> {
         ExpandMacros::new(self)
     }
 }
 
 impl<I> WithExpandMacros for I where I: Iterator<Item = Pos<Token>> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::Lexer, Token::*};
+
+    #[test]
+    fn newcommand_single_arg_substitution() {
+        let code = r"\newcommand{\u}[1]{2^#1} \u{x}";
+        let tokens: Vec<Token> = Lexer::new(code).expand_macros().map(|pos| pos.val).collect();
+
+        assert_eq!(
+            tokens,
+            vec![Whitespace(" ".into()), Char('2'), Superscript, Char('x')]
+        );
+    }
+
+    #[test]
+    fn brace_delimited_argument_can_contain_multiple_tokens() {
+        let code = r"\newcommand{\u}[1]{[#1]} \u{ab}";
+        let tokens: Vec<Token> = Lexer::new(code).expand_macros().map(|pos| pos.val).collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                Whitespace(" ".into()),
+                Char('['),
+                Char('a'),
+                Char('b'),
+                Char(']'),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_argument_skips_a_blank_line_before_a_braced_argument() {
+        let code = "\\def\\a#1{got:#1}\\a\n\n{x}";
+        let tokens: Vec<Token> = Lexer::new(code).expand_macros().map(|pos| pos.val).collect();
+
+        assert_eq!(
+            tokens,
+            vec![Char('g'), Char('o'), Char('t'), Char(':'), Char('x')]
+        );
+    }
+
+    #[test]
+    fn def_with_two_parameters_substitutes_in_body_order() {
+        let code = r"\def\pair#1#2{#2#1} \pair{a}{b}";
+        let tokens: Vec<Token> = Lexer::new(code).expand_macros().map(|pos| pos.val).collect();
+
+        assert_eq!(tokens, vec![Whitespace(" ".into()), Char('b'), Char('a')]);
+    }
+
+    #[test]
+    fn runaway_self_referential_macro_hits_the_depth_limit() {
+        let code = r"\def\a{\a} \a";
+        let tokens: Vec<Token> = ExpandMacros::with_max_depth(Lexer::new(code), 4)
+            .map(|pos| pos.val)
+            .collect();
+
+        assert_eq!(
+            tokens,
+            vec![Whitespace(" ".into()), Err(Error::ExpansionDepthExceeded)]
+        );
+    }
+}
@@ -1,5 +1,16 @@
-pub struct TexMacroCtx {}
-pub struct TexMacroResult {}
+use crate::Token;
+
+/// The arguments collected for a single invocation of a [`TexMacro`], one token list per
+/// parameter, in order (`args[0]` is `#1`, `args[1]` is `#2`, and so on).
+pub struct TexMacroCtx {
+    pub args: Vec<Vec<Token>>,
+}
+
+/// The tokens a [`TexMacro`] expands to. These are re-expanded once pushed back onto the
+/// gullet's token stream.
+pub struct TexMacroResult {
+    pub tokens: Vec<Token>,
+}
 
 /// Underlying function for a procedural TeX macro.
 pub type TexMacroFn = fn(TexMacroCtx) -> TexMacroResult;
@@ -8,4 +19,6 @@ pub type TexMacroFn = fn(TexMacroCtx) -> TexMacroResult;
 pub struct TexMacro {
     pub fun: TexMacroFn,
     pub names: &'static [&'static str],
+    /// Number of arguments `fun` expects, i.e. the highest `#n` it reads from its [`TexMacroCtx`].
+    pub arity: usize,
 }
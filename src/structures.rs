@@ -1,14 +1,14 @@
-use std::{fmt, iter};
+use std::{collections::HashMap, fmt, iter};
 
 /// Either left or right. Used to distinguish braces.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Side {
     Left,
     Right,
 }
 
 /// A single TeX token.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Token {
     /// A TeX control sequence/macro, e.g. `\mathbb`, `\newcommand`, or `\%`.
     Control(String),
@@ -20,53 +20,178 @@ pub enum Token {
     Ampersand,
     /// A collection of consecutive Unicode whitespace characters.
     Whitespace(String),
+    /// A paragraph break: a run of whitespace containing two or more line terminators, i.e. at
+    /// least one blank line.
+    Par,
     /// A TeX comment marked with `%`.
     Comment(String),
     /// A single, non-special character
     Char(char),
+    /// TeX's math-shift character, e.g. `$`.
+    MathShift,
+    /// TeX's superscript character, e.g. `^`.
+    Superscript,
+    /// TeX's subscript character, e.g. `_`.
+    Subscript,
+    /// A character with catcode `Active`, which behaves like a control sequence of one character.
+    Active(char),
     /// The EOF character (`\0`).
     Eof,
     /// Represents an error encountered in the lexical token stream.
     Err(Error),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
     /// Emitted when an illegal character is encountered.
     IllegalChar(char),
+    /// Emitted when macro expansion recurses past the configured depth limit,
+    /// most likely because a macro (directly or indirectly) expands to itself.
+    ExpansionDepthExceeded,
 }
 
-/// Represents a position within a source file.
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IllegalChar(ch) => write!(f, "illegal character {:?}", ch),
+            Error::ExpansionDepthExceeded => {
+                write!(f, "macro expansion exceeded the depth limit")
+            }
+        }
+    }
+}
+
+/// An [`Error`] paired with the [`Span`] it occurred at, as accumulated by e.g. [`crate::lexer::Lexer`]
+/// instead of being surfaced inline in the token stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub error: Error,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(error: Error, span: Span) -> Diagnostic {
+        Diagnostic { error, span }
+    }
+}
+
+/// Renders a batch of diagnostics against the original source into one combined report, in the
+/// style of a recovering parser that keeps going past an error and reports everything it found:
+/// a single pass over `code`, with every diagnostic's underline inserted after its own line.
+pub fn render_diagnostics(diagnostics: &[Diagnostic], code: &str) -> String {
+    let mut by_line: HashMap<usize, Vec<&Diagnostic>> = HashMap::new();
+    for diagnostic in diagnostics {
+        by_line
+            .entry(diagnostic.span.lo().lin)
+            .or_default()
+            .push(diagnostic);
+    }
+
+    let mut result = String::new();
+    for (i, line) in code.lines().enumerate() {
+        result += line;
+        result += "\n";
+        if let Some(line_diagnostics) = by_line.get(&(i + 1)) {
+            for diagnostic in line_diagnostics {
+                result += &annotation_line(&diagnostic.span, &diagnostic.error.to_string());
+            }
+        }
+    }
+    result
+}
+
+/// A single position within a source file: 1-indexed line and column, plus the absolute byte
+/// offset from the start of the source (the latter is what makes ranges cheap to compare and
+/// slice without re-walking the source).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourcePos {
+    pub lin: usize,
+    pub col: usize,
+    pub byte: usize,
+}
+
+impl SourcePos {
+    pub fn new(lin: usize, col: usize, byte: usize) -> SourcePos {
+        SourcePos { lin, col, byte }
+    }
+}
+
+/// A byte-offset range within a source file, from its first character (`lo`) to its last (`hi`).
+/// Borrows the source-map model used by proc-macro2's fallback lexer so that even multi-character
+/// tokens (e.g. `Control("newcommand")`) can be underlined precisely.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Span {
-    lin: usize,
-    col: usize,
+    lo: SourcePos,
+    hi: SourcePos,
 }
 
 impl Span {
-    pub fn new(lin: usize, col: usize) -> Span {
-        Span { lin, col }
+    pub fn new(lo: SourcePos, hi: SourcePos) -> Span {
+        Span { lo, hi }
+    }
+
+    /// A zero-width span sitting at a single position.
+    pub fn at(pos: SourcePos) -> Span {
+        Span { lo: pos, hi: pos }
+    }
+
+    pub fn lo(&self) -> SourcePos {
+        self.lo
+    }
+
+    pub fn hi(&self) -> SourcePos {
+        self.hi
     }
 
-    /// Highlights the encoded position within a given body of text with `^`, followed by a message.
+    /// Combines two spans into one running from this span's start to `other`'s end. Assumes
+    /// `other` comes no earlier in the source than `self`.
+    pub fn join(&self, other: &Span) -> Span {
+        Span {
+            lo: self.lo,
+            hi: other.hi,
+        }
+    }
+
+    /// Highlights the encoded range within a given body of text with a run of `^` matching its
+    /// width, followed by a message.
     pub fn highlight_msg_in_code(&self, code: &str, msg: &str) -> String {
         let mut result = String::new();
-        let mut i = 1;
-        for line in code.lines() {
+        for (i, line) in code.lines().enumerate() {
             result += line;
             result += "\n";
-            if i == self.lin {
-                let padding: String = iter::repeat(' ').take(self.col - 1).collect();
-                result = format!("{}{}^ {}\n", result, padding, msg);
+            if i + 1 == self.lo.lin {
+                result += &annotation_line(self, msg);
             }
-            i += 1;
         }
         result
     }
 }
 
+/// Renders the padding, `^` run, and message for a single span, as inserted immediately below
+/// the source line it annotates. Shared by [`Span::highlight_msg_in_code`] and
+/// [`render_diagnostics`] so both annotate a line the same way.
+fn annotation_line(span: &Span, msg: &str) -> String {
+    let padding: String = iter::repeat(' ').take(span.lo.col - 1).collect();
+    let width = if span.hi.lin == span.lo.lin {
+        span.hi.col.saturating_sub(span.lo.col) + 1
+    } else {
+        1
+    };
+    let underline: String = iter::repeat('^').take(width).collect();
+    format!("{}{} {}\n", padding, underline, msg)
+}
+
 impl fmt::Display for Span {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "line {}, column {}", self.lin, self.col)
+        if self.lo == self.hi {
+            write!(f, "line {}, column {}", self.lo.lin, self.lo.col)
+        } else {
+            write!(
+                f,
+                "line {}, column {} to line {}, column {}",
+                self.lo.lin, self.lo.col, self.hi.lin, self.hi.col
+            )
+        }
     }
 }
 
@@ -97,9 +222,9 @@ pub enum Mode {
 mod tests {
     #[test]
     fn span_highlight_msg_in_code() {
-        use crate::Span;
+        use crate::{SourcePos, Span};
 
-        let span = Span::new(2, 30);
+        let span = Span::at(SourcePos::new(2, 30, 0));
         let test_code = r#"\begin{equation*}
     \textbf{illegal} \rarrow # \larrow \textbf{illegal}
 \end{equation*}"#;
@@ -113,4 +238,75 @@ mod tests {
         .into();
         assert_eq!(highlighted, expected_highlighted);
     }
+
+    #[test]
+    fn span_highlight_msg_in_code_underlines_a_multi_character_token() {
+        use crate::{SourcePos, Span};
+
+        // A span covering all 11 characters of `\newcommand`, starting at column 6.
+        let span = Span::new(SourcePos::new(2, 6, 0), SourcePos::new(2, 16, 0));
+        let test_code = "before\n     \\newcommand\nafter";
+
+        let highlighted = span.highlight_msg_in_code(test_code, "Undefined control sequence");
+        let expected_highlighted: String = concat!(
+            "before\n",
+            "     \\newcommand\n",
+            "     ^^^^^^^^^^^ Undefined control sequence\n",
+            "after\n",
+        )
+        .into();
+        assert_eq!(highlighted, expected_highlighted);
+    }
+
+    #[test]
+    fn span_join_spans_adjacent_range() {
+        use crate::{SourcePos, Span};
+
+        let first = Span::at(SourcePos::new(1, 1, 0));
+        let last = Span::at(SourcePos::new(1, 11, 10));
+        let joined = first.join(&last);
+
+        assert_eq!(joined.lo(), first.lo());
+        assert_eq!(joined.hi(), last.hi());
+    }
+
+    #[test]
+    fn render_diagnostics_combines_every_highlight() {
+        use crate::{render_diagnostics, Diagnostic, Error, SourcePos, Span};
+
+        let code = "a#\nb#";
+        let diagnostics = vec![
+            Diagnostic::new(Error::IllegalChar('#'), Span::at(SourcePos::new(1, 2, 1))),
+            Diagnostic::new(Error::IllegalChar('#'), Span::at(SourcePos::new(2, 2, 4))),
+        ];
+
+        let report = render_diagnostics(&diagnostics, code);
+
+        assert_eq!(report.matches("illegal character '#'").count(), 2);
+    }
+
+    #[test]
+    fn render_diagnostics_prints_the_source_only_once() {
+        use crate::{render_diagnostics, Diagnostic, Error, SourcePos, Span};
+
+        let code = "a#\nb#\nc";
+        let diagnostics = vec![
+            Diagnostic::new(Error::IllegalChar('#'), Span::at(SourcePos::new(1, 2, 1))),
+            Diagnostic::new(Error::IllegalChar('#'), Span::at(SourcePos::new(2, 2, 4))),
+        ];
+
+        let report = render_diagnostics(&diagnostics, code);
+
+        assert_eq!(report.lines().filter(|&line| line == "c").count(), 1);
+        assert_eq!(
+            report,
+            concat!(
+                "a#\n",
+                " ^ illegal character '#'\n",
+                "b#\n",
+                " ^ illegal character '#'\n",
+                "c\n",
+            )
+        );
+    }
 }
@@ -1,6 +1,6 @@
 use std::iter::FusedIterator;
 
-use crate::{Pos, Span};
+use crate::{Pos, SourcePos, Span};
 
 pub struct PosChars<I>
 where
@@ -9,6 +9,7 @@ where
     chars: I,
     lin: usize,
     col: usize,
+    byte: usize,
 }
 
 impl<I> Iterator for PosChars<I>
@@ -19,13 +20,18 @@ where
 
     fn next(&mut self) -> Option<Pos<char>> {
         self.chars.next().map(|ch| {
-            let result = Pos::new(ch, Span::new(self.lin, self.col));
+            let lo = SourcePos::new(self.lin, self.col, self.byte);
+            let hi = SourcePos::new(self.lin, self.col, self.byte + ch.len_utf8() - 1);
+            let result = Pos::new(ch, Span::new(lo, hi));
+
+            self.byte += ch.len_utf8();
             if ch == '\n' {
-                self.lin = 1;
-                self.col += 1;
-            } else {
                 self.lin += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
             }
+
             result
         })
     }
@@ -39,6 +45,7 @@ pub trait WithPosChars: Iterator<Item = char> + Sized {
             chars: self,
             lin: 1,
             col: 1,
+            byte: 0,
         }
     }
 }
@@ -1,6 +1,6 @@
 extern crate proc_macro;
 
-use proc_macro2::{Ident, TokenStream};
+use proc_macro2::{Ident, Span, TokenStream};
 use quote::{format_ident, quote, ToTokens};
 use syn::{
     braced, parenthesized,
@@ -9,7 +9,9 @@ use syn::{
     punctuated::Punctuated,
     spanned::Spanned,
     token::{Comma, Mut},
-    Attribute, Block, FnArg, Lit, ReturnType, Stmt, Token, Type, Visibility,
+    visit::{self, Visit},
+    Attribute, Block, Expr, ExprIndex, ExprLit, FnArg, Lit, Member, ReturnType, Stmt, Token, Type,
+    Visibility,
 };
 
 /// Marks a TeX macro function.
@@ -20,59 +22,66 @@ pub fn tex_macro(
     attr: proc_macro::TokenStream,
     input: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
-    fn tex_macro2(attr: TokenStream, input: TokenStream) -> parse::Result<TokenStream> {
-        let fun = parse2::<AbstractFun>(input)?;
-        fun.validate()?;
-
-        let name = if attr.is_empty() {
-            fun.name.to_string_non_raw()
-        } else {
-            parse2::<Lit>(attr).and_then(|lit| match lit {
+    tex_macro2(attr.into(), input.into())
+        .unwrap_or_else(|err| TokenStream::from(err.to_compile_error()))
+        .into()
+}
+
+fn tex_macro2(attr: TokenStream, input: TokenStream) -> parse::Result<TokenStream> {
+    let fun = parse2::<AbstractFun>(input)?;
+    let ctx_arity = CtxArity::derive(&fun)?;
+    fun.validate(&ctx_arity)?;
+    let arity = ctx_arity.value;
+
+    let names: Vec<String> = if attr.is_empty() {
+        vec![fun.name.to_string_non_raw()]
+    } else {
+        let Names(lits) = parse2::<Names>(attr)?;
+        lits.into_iter()
+            .map(|lit| match lit {
                 Lit::Str(ls) => Ok(ls.value()),
                 _ => Err(parse::Error::new(
                     lit.span(),
                     format_args!("Only takes string arguments, instead got {:?}", lit),
                 )),
-            })?
+            })
+            .collect::<parse::Result<Vec<String>>>()?
+    };
+
+    let primary_name = &names[0];
+    let macro_name = Ident::new(primary_name, fun.name.span());
+    let fun_name = format_ident!("macro_{}", primary_name);
+
+    let AbstractFun {
+        visibility,
+        name: _,
+        args,
+        attributes,
+        cooked,
+        ret,
+        body,
+    } = fun;
+
+    let tex_macro_path = quote!(watex::macros::TexMacro);
+
+    Ok(quote! {
+        #(#cooked)*
+        #[allow(missing_docs)]
+        #[allow(non_upper_case_globals)]
+        pub static #macro_name: #tex_macro_path = #tex_macro_path {
+            fun: #fun_name,
+            names: &[#(#names),*],
+            arity: #arity,
         };
 
-        let macro_name = Ident::new(&name, fun.name.span());
-        let fun_name = format_ident!("macro_{}", name);
-
-        let AbstractFun {
-            visibility,
-            name: _,
-            args,
-            attributes,
-            cooked,
-            ret,
-            body,
-        } = fun;
-
-        let tex_macro_path = quote!(watex::macros::TexMacro);
-
-        Ok(quote! {
-            #(#cooked)*
-            #[allow(missing_docs)]
-            #[allow(non_upper_case_globals)]
-            pub static #macro_name: #tex_macro_path = #tex_macro_path {
-                fun: #fun_name,
-                names: &[#name], // TODO: Aliases
-            };
-
-            #(#cooked)*
-            #(#attributes)*
-            #[allow(missing_docs)]
-            #[allow(non_snake_case)]
-            #visibility fn #fun_name (#(#args),*) -> #ret {
-                #(#body)*
-            }
-        })
-    }
-
-    tex_macro2(attr.into(), input.into())
-        .unwrap_or_else(|err| TokenStream::from(err.to_compile_error()))
-        .into()
+        #(#cooked)*
+        #(#attributes)*
+        #[allow(missing_docs)]
+        #[allow(non_snake_case)]
+        #visibility fn #fun_name (#(#args),*) -> #ret {
+            #(#body)*
+        }
+    })
 }
 
 /// An abstract structure to represent a parsed function/
@@ -88,25 +97,36 @@ struct AbstractFun {
 }
 
 impl AbstractFun {
-    fn validate(&self) -> parse::Result<()> {
-        // TODO: Validation step.
-        const TEX_MACRO_MAX_ARGS: usize = 2;
+    /// Checks that the function's real TeX arity (as derived by [`CtxArity::derive`]) is usable:
+    /// within the argument limit, and with no gaps in the `ctx.args[n]` indices it reads.
+    fn validate(&self, arity: &CtxArity) -> parse::Result<()> {
+        const TEX_MACRO_MAX_ARGS: usize = 9;
 
-        // Declaration
-        if self.args.len() > TEX_MACRO_MAX_ARGS {
+        if arity.value > TEX_MACRO_MAX_ARGS {
             return Err(parse::Error::new(
-                self.args
-                    .last()
-                    .expect("Multiple arguments required")
-                    .span(),
+                arity.highest_span(),
                 format_args!(
-                    "Function's arity exceeds more than {} arguments.",
+                    "Macro's arity exceeds more than {} arguments.",
                     TEX_MACRO_MAX_ARGS
                 ),
             ));
         }
 
-        // ...
+        // TeX parameters are positional (`#1`..`#n`), so skipping an index (e.g. reading
+        // `ctx.args[2]` but never `ctx.args[1]`) leaves no sensible binding for the gap.
+        let mut seen = vec![false; arity.value];
+        for &(i, _) in &arity.indices {
+            seen[i] = true;
+        }
+        if let Some(missing) = seen.iter().position(|seen| !seen) {
+            return Err(parse::Error::new(
+                arity.highest_span(),
+                format_args!(
+                    "Inconsistent arity: `ctx.args[{}]` is never read, but a higher index is used.",
+                    missing
+                ),
+            ));
+        }
 
         // Return type can't be validated since macros don't have access to the
         // type system
@@ -115,6 +135,89 @@ impl AbstractFun {
     }
 }
 
+/// A macro's real TeX arity, together with every `ctx.args[n]` index its body reads.
+///
+/// The Rust parameter count of the annotated function (`AbstractFun::args.len()`) is useless for
+/// this: every function that coerces to `TexMacroFn` takes exactly one `TexMacroCtx` parameter,
+/// no matter how many TeX arguments its macro actually reads. The real arity is discovered by
+/// scanning the body for `ctx.args[n]` indexing expressions, where `ctx` is that one parameter's
+/// name.
+struct CtxArity {
+    value: usize,
+    indices: Vec<(usize, Span)>,
+}
+
+impl CtxArity {
+    fn derive(fun: &AbstractFun) -> parse::Result<CtxArity> {
+        let ctx_arg = fun.args.first().ok_or_else(|| {
+            parse::Error::new(
+                fun.name.span(),
+                "tex_macro functions must take exactly one `TexMacroCtx` argument",
+            )
+        })?;
+        let ctx_ident = &ctx_arg.name;
+
+        let mut visitor = CtxArgIndices {
+            ctx_ident,
+            indices: Vec::new(),
+        };
+        for stmt in &fun.body {
+            visitor.visit_stmt(stmt);
+        }
+
+        let value = visitor
+            .indices
+            .iter()
+            .map(|(i, _)| i + 1)
+            .max()
+            .unwrap_or(0);
+
+        Ok(CtxArity {
+            value,
+            indices: visitor.indices,
+        })
+    }
+
+    /// The span of the `ctx.args[n]` expression with the highest `n`, used to point validation
+    /// errors at the argument responsible for the current arity.
+    fn highest_span(&self) -> Span {
+        self.indices
+            .iter()
+            .max_by_key(|(i, _)| *i)
+            .map(|(_, span)| *span)
+            .expect("arity > 0 implies at least one indexed access")
+    }
+}
+
+/// Walks a macro function's body looking for `ctx.args[n]` indexing expressions, where `ctx` is
+/// the name of the function's single [`TexMacroCtx`] parameter. Used by [`CtxArity::derive`].
+struct CtxArgIndices<'a> {
+    ctx_ident: &'a Ident,
+    indices: Vec<(usize, Span)>,
+}
+
+impl<'ast> Visit<'ast> for CtxArgIndices<'_> {
+    fn visit_expr_index(&mut self, node: &'ast ExprIndex) {
+        if let Expr::Field(field) = &*node.expr {
+            if let Expr::Path(path) = &*field.base {
+                let is_ctx_args = path.path.is_ident(self.ctx_ident)
+                    && matches!(&field.member, Member::Named(member) if member == "args");
+                if is_ctx_args {
+                    if let Expr::Lit(ExprLit {
+                        lit: Lit::Int(n), ..
+                    }) = &*node.index
+                    {
+                        if let Ok(i) = n.base10_parse::<usize>() {
+                            self.indices.push((i, n.span()));
+                        }
+                    }
+                }
+            }
+        }
+        visit::visit_expr_index(self, node);
+    }
+}
+
 impl Parse for AbstractFun {
     fn parse(input: ParseStream<'_>) -> parse::Result<Self> {
         let (cooked, attributes) = partition_cooked(input.call(Attribute::parse_outer)?);
@@ -123,7 +226,6 @@ impl Parse for AbstractFun {
         let visibility = input.parse::<Visibility>()?;
         input.parse::<Token![fn]>()?;
         let name = input.parse::<Ident>()?;
-        println!("{}", name);
         let args = input
             .parse::<Parenthesized<FnArg>>()
             .map(|p| {
@@ -252,6 +354,17 @@ impl<T: Parse> Parse for Parenthesized<T> {
     }
 }
 
+/// A bare, comma-separated list of literals, as found inside `#[tex_macro(...)]`'s attribute
+/// arguments (which arrive with no surrounding parentheses to strip).
+#[derive(Debug)]
+struct Names(Punctuated<Lit, Comma>);
+
+impl Parse for Names {
+    fn parse(input: ParseStream<'_>) -> parse::Result<Self> {
+        input.parse_terminated(Lit::parse).map(Names)
+    }
+}
+
 trait ToStringNonRaw: Sized + ToString {
     fn to_string_non_raw(&self) -> String {
         self.to_string().trim_start_matches("r#").into()
@@ -259,3 +372,104 @@ trait ToStringNonRaw: Sized + ToString {
 }
 
 impl<T: Sized + ToString> ToStringNonRaw for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_fun(src: &str) -> AbstractFun {
+        parse2::<AbstractFun>(src.parse().expect("should tokenize")).expect("should parse")
+    }
+
+    #[test]
+    fn arity_is_derived_from_the_highest_ctx_arg_index() {
+        let fun = parse_fun(
+            r#"
+            fn macro_geq(ctx: TexMacroCtx) -> TexMacroResult {
+                let _ = &ctx.args[0];
+                let _ = &ctx.args[1];
+                TexMacroResult { tokens: Vec::new() }
+            }
+            "#,
+        );
+
+        assert_eq!(CtxArity::derive(&fun).unwrap().value, 2);
+    }
+
+    #[test]
+    fn arity_is_zero_when_ctx_args_is_never_indexed() {
+        let fun = parse_fun(
+            r#"
+            fn macro_noop(ctx: TexMacroCtx) -> TexMacroResult {
+                TexMacroResult { tokens: Vec::new() }
+            }
+            "#,
+        );
+
+        assert_eq!(CtxArity::derive(&fun).unwrap().value, 0);
+    }
+
+    #[test]
+    fn rejects_a_gap_in_ctx_arg_indices() {
+        let fun = parse_fun(
+            r#"
+            fn macro_gappy(ctx: TexMacroCtx) -> TexMacroResult {
+                let _ = &ctx.args[0];
+                let _ = &ctx.args[2];
+                TexMacroResult { tokens: Vec::new() }
+            }
+            "#,
+        );
+
+        let arity = CtxArity::derive(&fun).unwrap();
+        let message = fun.validate(&arity).unwrap_err().to_string();
+        assert!(message.contains("ctx.args[1]"));
+    }
+
+    #[test]
+    fn rejects_arity_above_the_limit() {
+        let fun = parse_fun(
+            r#"
+            fn macro_too_many(ctx: TexMacroCtx) -> TexMacroResult {
+                let _ = &ctx.args[9];
+                TexMacroResult { tokens: Vec::new() }
+            }
+            "#,
+        );
+
+        let arity = CtxArity::derive(&fun).unwrap();
+        let message = fun.validate(&arity).unwrap_err().to_string();
+        assert!(message.contains("exceeds"));
+    }
+
+    #[test]
+    fn rejects_a_zero_argument_function_instead_of_panicking() {
+        let fun = parse_fun(
+            r#"
+            fn macro_argless() -> TexMacroResult {
+                TexMacroResult { tokens: Vec::new() }
+            }
+            "#,
+        );
+
+        let message = CtxArity::derive(&fun).unwrap_err().to_string();
+        assert!(message.contains("TexMacroCtx"));
+    }
+
+    #[test]
+    fn tex_macro2_emits_every_alias_into_names() {
+        let output = tex_macro2(
+            quote::quote!("geq", "ge"),
+            quote::quote! {
+                fn geq(ctx: TexMacroCtx) -> TexMacroResult {
+                    TexMacroResult { tokens: Vec::new() }
+                }
+            },
+        )
+        .expect("should expand");
+
+        let rendered = output.to_string();
+        assert!(rendered.contains("\"geq\""));
+        assert!(rendered.contains("\"ge\""));
+    }
+}